@@ -0,0 +1,136 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use daggy::petgraph::visit::EdgeRef;
+use daggy::petgraph::Direction;
+use daggy::{Dag, NodeIndex};
+use rayon::prelude::*;
+
+use interner::Sym;
+use EdgeData;
+
+/// Direct reverse-dependency counts for a single crate: how many other
+/// crates (at their latest version) depend on it by default vs. only
+/// by way of an optional dependency.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RevDepCount {
+    pub def: u16,
+    pub opt: u16,
+}
+
+/// Everything we know about who depends on a single crate.
+#[derive(Debug, Clone, Default)]
+pub struct RevDependencies {
+    pub direct: RevDepCount,
+    pub transitive: HashSet<Sym>,
+}
+
+/// Reverse-dependency statistics computed over the whole dependency DAG.
+#[derive(Debug, Clone, Default)]
+pub struct DepsStats {
+    pub total: usize,
+    pub counts: HashMap<Sym, RevDependencies>,
+}
+
+impl DepsStats {
+    /// Compute reverse-dependency stats for every crate in `dag`.
+    ///
+    /// `latest_nodes` maps each crate to the node of its latest version;
+    /// only those nodes' outgoing edges count toward the direct `def`/`opt`
+    /// tallies, matching cargo's "only the newest version matters" behavior.
+    /// The transitive closure, however, is followed through every version
+    /// node in the graph, using `nodes_by_sym` (every node for a crate) to
+    /// seed that search without scanning the whole dag per crate.
+    pub fn compute(
+        dag: &Dag<Sym, EdgeData>,
+        latest_nodes: &HashMap<Sym, NodeIndex>,
+        nodes_by_sym: &HashMap<Sym, Vec<NodeIndex>>,
+    ) -> DepsStats {
+        let direct: HashMap<Sym, RevDepCount> = latest_nodes
+            .par_iter()
+            .map(|(_, &idx)| {
+                let mut counts = HashMap::new();
+                for edge in dag.graph().edges_directed(idx, Direction::Outgoing) {
+                    let target = dag.graph()[edge.target()];
+                    let entry = counts
+                        .entry(target)
+                        .or_insert_with(RevDepCount::default);
+                    if edge.weight().optional {
+                        entry.opt += 1;
+                    } else {
+                        entry.def += 1;
+                    }
+                }
+                counts
+            })
+            .reduce(HashMap::new, |mut a, b| {
+                for (sym, count) in b {
+                    let entry = a.entry(sym).or_insert_with(RevDepCount::default);
+                    entry.def += count.def;
+                    entry.opt += count.opt;
+                }
+                a
+            });
+
+        let mut counts = HashMap::new();
+        for &sym in latest_nodes.keys() {
+            let transitive = transitive_dependents(dag, sym, nodes_by_sym);
+            counts.insert(
+                sym,
+                RevDependencies {
+                    direct: direct.get(&sym).cloned().unwrap_or_default(),
+                    transitive,
+                },
+            );
+        }
+
+        DepsStats {
+            total: latest_nodes.len(),
+            counts,
+        }
+    }
+
+    /// The crates with the most direct reverse dependencies, most first.
+    pub fn most_depended_upon(&self, limit: usize) -> Vec<(Sym, &RevDependencies)> {
+        let mut ranked: Vec<_> = self
+            .counts
+            .iter()
+            .map(|(&sym, deps)| (sym, deps))
+            .collect();
+        ranked.sort_by(|a, b| {
+            let a_total = a.1.direct.def + a.1.direct.opt;
+            let b_total = b.1.direct.def + b.1.direct.opt;
+            b_total.cmp(&a_total)
+        });
+        ranked.truncate(limit);
+        ranked
+    }
+}
+
+/// BFS over the inverted graph starting from every node for `sym`,
+/// collecting the set of dependent crates (deduped by crate, not by
+/// version). `nodes_by_sym` must map `sym` to every node weighted with it,
+/// so the search can seed its queue directly instead of scanning every
+/// node in the dag to find them.
+fn transitive_dependents(
+    dag: &Dag<Sym, EdgeData>,
+    sym: Sym,
+    nodes_by_sym: &HashMap<Sym, Vec<NodeIndex>>,
+) -> HashSet<Sym> {
+    let mut seen = HashSet::new();
+    let mut visited_nodes = HashSet::new();
+    let mut queue: VecDeque<NodeIndex> = nodes_by_sym
+        .get(&sym)
+        .map(|nodes| nodes.iter().cloned().collect())
+        .unwrap_or_default();
+    while let Some(idx) = queue.pop_front() {
+        if !visited_nodes.insert(idx) {
+            continue;
+        }
+        for edge in dag.graph().edges_directed(idx, Direction::Incoming) {
+            let dependent = dag.graph()[edge.source()];
+            seen.insert(dependent);
+            queue.push_back(edge.source());
+        }
+    }
+    seen
+}
@@ -0,0 +1,127 @@
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use failure::{Error, ResultExt};
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use reqwest;
+use sha2::{Digest, Sha256};
+
+use CrateVersion;
+
+const CRATES_IO_DL: &str = "https://crates.io/api/v1/crates";
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Outcome of verifying (and possibly downloading) a single `.crate` file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Outcome {
+    AlreadyVerified,
+    Downloaded,
+    ChecksumMismatch,
+}
+
+/// Tally of what happened while verifying a batch of crate files.
+#[derive(Debug, Clone, Default)]
+pub struct DownloadReport {
+    pub already_verified: usize,
+    pub downloaded: usize,
+    pub mismatches: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+/// Download and checksum-verify the `.crate` tarball for every version in
+/// `versions`, storing files under `dest_dir` as `<name>-<version>.crate`.
+/// Versions already present on disk with a matching checksum are skipped,
+/// so re-running is incremental. Runs with up to `concurrency` downloads in
+/// flight at once.
+pub fn verify_crate_files<P: AsRef<Path>>(
+    versions: &[CrateVersion],
+    dest_dir: P,
+    concurrency: usize,
+) -> Result<DownloadReport, Error> {
+    let dest_dir = dest_dir.as_ref();
+    fs::create_dir_all(dest_dir).with_context(|e| {
+        format!("Failed to create {:?}: {}", dest_dir, e)
+    })?;
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(concurrency)
+        .build()
+        .context("Failed to build download thread pool")?;
+    let client = reqwest::Client::new();
+
+    let results: Vec<_> = pool.install(|| {
+        versions.par_iter()
+            .map(|v| (v, verify_one(&client, v, dest_dir)))
+            .collect()
+    });
+
+    let mut report = DownloadReport::default();
+    for (v, result) in results {
+        match result {
+            Ok(Outcome::AlreadyVerified) => report.already_verified += 1,
+            Ok(Outcome::Downloaded) => report.downloaded += 1,
+            Ok(Outcome::ChecksumMismatch) => {
+                report.mismatches.push(format!("{} {}", v.name, v.version));
+            }
+            Err(e) => report.errors.push(format!("{} {}: {}", v.name, v.version, e)),
+        }
+    }
+    Ok(report)
+}
+
+fn verify_one(client: &reqwest::Client, v: &CrateVersion, dest_dir: &Path) -> Result<Outcome, Error> {
+    let path = dest_dir.join(format!("{}-{}.crate", v.name, v.version));
+    if path.exists() && sha256_hex(&fs::read(&path)?) == v.cksum {
+        return Ok(Outcome::AlreadyVerified);
+    }
+
+    let url = format!("{}/{}/{}/download", CRATES_IO_DL, v.name, v.version);
+    let bytes = download_with_retries(client, &url)?;
+    File::create(&path)?.write_all(&bytes)?;
+
+    if sha256_hex(&bytes) == v.cksum {
+        Ok(Outcome::Downloaded)
+    } else {
+        Ok(Outcome::ChecksumMismatch)
+    }
+}
+
+/// Download `url`, retrying transient failures (connection errors,
+/// timeouts, 5xx responses) with exponential backoff. A 4xx response means
+/// the file is permanently missing (e.g. yanked off the CDN), so it's
+/// reported immediately instead of burning through the retry budget.
+fn download_with_retries(client: &reqwest::Client, url: &str) -> Result<Vec<u8>, Error> {
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        match client.get(url).send().and_then(|resp| resp.error_for_status()) {
+            Ok(mut resp) => {
+                let mut buf = Vec::new();
+                resp.read_to_end(&mut buf).with_context(|e| {
+                    format!("Failed to read response body from {}: {}", url, e)
+                })?;
+                return Ok(buf);
+            }
+            Err(e) => {
+                if e.status().map_or(false, |s| s.is_client_error()) {
+                    return Err(e.into());
+                }
+                if attempt >= MAX_ATTEMPTS {
+                    return Err(e.into());
+                }
+                let backoff = Duration::from_millis(200 * 2u64.pow(attempt));
+                println!("Download of {} failed ({}), retrying in {:?}", url, e, backoff);
+                thread::sleep(backoff);
+            }
+        }
+    }
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    let mut hasher = Sha256::default();
+    hasher.input(bytes);
+    hasher.result().iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,218 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use daggy::{Dag, NodeIndex};
+use semver::Version;
+
+use interner::{Interner, Sym};
+use {resolve_version, Crate, CrateVersion, DependencyKind, EdgeData};
+
+/// Builds feature-aware dependency subgraphs: the actual closure of crates
+/// cargo would compile for a given feature selection, as opposed to the
+/// "every non-dev dep always present" graph `build_dag` produces.
+pub struct FeatureGraphBuilder<'c> {
+    crates_by_sym: &'c [Crate],
+}
+
+impl<'c> FeatureGraphBuilder<'c> {
+    pub fn new(crates_by_sym: &'c [Crate]) -> FeatureGraphBuilder<'c> {
+        FeatureGraphBuilder { crates_by_sym }
+    }
+
+    /// Build the dependency closure cargo would compile for `root` with
+    /// `requested_features` active (include `"default"` in the list
+    /// yourself if you want the default feature set expanded too).
+    pub fn build_feature_graph(&self, root: &'c CrateVersion, requested_features: &[String],
+                                interner: &mut Interner)
+        -> Dag<Sym, EdgeData>
+    {
+        let mut dag = Dag::new();
+        let mut nodes = HashMap::new();
+        let mut visited = HashMap::new();
+        self.visit(root, requested_features, interner, &mut dag, &mut nodes, &mut visited);
+        dag
+    }
+
+    fn visit(
+        &self,
+        version: &'c CrateVersion,
+        requested_features: &[String],
+        interner: &mut Interner,
+        dag: &mut Dag<Sym, EdgeData>,
+        nodes: &mut HashMap<Sym, NodeIndex>,
+        visited: &mut HashMap<(Sym, Version), HashSet<String>>,
+    ) {
+        let sym = interner.intern(&version.name);
+        // Features are unified across the graph: a version reached again
+        // with features it hasn't been visited with is revisited with the
+        // union, so optional deps those features switch on are picked up.
+        let key = (sym, version.version.clone());
+        let requested: Vec<String> = match visited.get(&key) {
+            Some(seen) if requested_features.iter().all(|f| seen.contains(f)) => return,
+            Some(seen) => seen.iter().chain(requested_features).cloned().collect(),
+            None => requested_features.to_vec(),
+        };
+        visited.insert(key, requested.iter().cloned().collect());
+        let idx = Self::node_for(sym, dag, nodes);
+
+        let active = expand_features(&version.features, &requested);
+        let activated_optional = optional_activations(&active);
+
+        for dep in &version.deps {
+            if dep.kind == DependencyKind::Dev || dep.registry.is_some() {
+                continue;
+            }
+            let extra_features = activated_optional.get(dep.name.as_str());
+            let strongly_activated = extra_features.map_or(false, |a| a.strong);
+            if dep.optional && !active.contains(&dep.name) && !strongly_activated {
+                // Optional and never switched on by any active feature. A
+                // weak `"dep_name?/feat"` entry alone doesn't count here —
+                // it only forwards a feature to a dep that's already active
+                // for some other reason.
+                continue;
+            }
+
+            let real_name = dep.package.as_ref().unwrap_or(&dep.name);
+            let dep_sym = interner.intern(real_name);
+            let target_crate = self.crates_by_sym.get(dep_sym.index())
+                .filter(|c| c.name == *real_name);
+            let (_, target_version) = match target_crate.and_then(|c| resolve_version(&c.versions, &dep.req)) {
+                Some(r) => r,
+                None => continue,
+            };
+
+            let dep_idx = Self::node_for(dep_sym, dag, nodes);
+            let edge = EdgeData { optional: dep.optional, kind: dep.kind };
+            // Nodes are per crate, not per version, so two versions of one
+            // crate depending on each other also show up as a cycle; such an
+            // edge is dropped rather than failing. An edge already added on
+            // an earlier visit is not added again.
+            if dag.find_edge(idx, dep_idx).is_none() {
+                let _ = dag.add_edge(idx, dep_idx, edge);
+            }
+
+            let mut dep_requested: Vec<String> = if dep.default_features {
+                vec!["default".to_string()]
+            } else {
+                Vec::new()
+            };
+            dep_requested.extend(dep.features.iter().cloned());
+            if let Some(extra) = extra_features {
+                dep_requested.extend(extra.features.iter().cloned());
+            }
+
+            self.visit(target_version, &dep_requested, interner, dag, nodes, visited);
+        }
+    }
+
+    fn node_for(sym: Sym, dag: &mut Dag<Sym, EdgeData>, nodes: &mut HashMap<Sym, NodeIndex>) -> NodeIndex {
+        *nodes.entry(sym).or_insert_with(|| dag.add_node(sym))
+    }
+}
+
+/// Expand a requested feature set into the flat set of active feature
+/// strings, recursively following entries that just reference another
+/// named feature set (as `"default"` typically does).
+fn expand_features(features: &HashMap<String, Vec<String>>, requested: &[String]) -> HashSet<String> {
+    let mut active = HashSet::new();
+    let mut queue: VecDeque<String> = requested.iter().cloned().collect();
+    while let Some(feat) = queue.pop_front() {
+        if !active.insert(feat.clone()) {
+            continue;
+        }
+        if let Some(implied) = features.get(&feat) {
+            queue.extend(implied.iter().cloned());
+        }
+    }
+    active
+}
+
+/// Feature-forwarding info for one optional dependency, collected from
+/// `"dep:name"` and `"dep_name/feat"` entries in an active feature set.
+#[derive(Debug, Default)]
+struct OptionalActivation {
+    /// Whether some entry activates the dependency outright: `"dep:name"`
+    /// or a strong `"dep_name/feat"`. A weak `"dep_name?/feat"` entry does
+    /// *not* set this — per cargo semantics it only forwards `feat` to the
+    /// dependency if something else already activated it.
+    strong: bool,
+    features: Vec<String>,
+}
+
+/// Pull the `"dep:name"` and `"dep_name[?]/feat"` entries out of an active
+/// feature set: these activate an optional dependency even though its name
+/// never appears as a feature by itself, optionally requesting extra
+/// features on it.
+fn optional_activations(active: &HashSet<String>) -> HashMap<&str, OptionalActivation> {
+    let mut activations: HashMap<&str, OptionalActivation> = HashMap::new();
+    for feat in active {
+        if feat.starts_with("dep:") {
+            activations.entry(&feat[4..]).or_insert_with(OptionalActivation::default).strong = true;
+        } else if let Some(slash) = feat.find('/') {
+            let raw_dep = &feat[..slash];
+            let weak = raw_dep.ends_with('?');
+            let dep_name = raw_dep.trim_end_matches('?');
+            let dep_feat = &feat[slash + 1..];
+            let entry = activations.entry(dep_name).or_insert_with(OptionalActivation::default);
+            entry.features.push(dep_feat.to_string());
+            if !weak {
+                entry.strong = true;
+            }
+        }
+    }
+    activations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(feats: &[&str]) -> HashSet<String> {
+        feats.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn optional_activations_dep_colon_is_strong() {
+        let activations = optional_activations(&set(&["dep:serde"]));
+        let serde = &activations["serde"];
+        assert!(serde.strong);
+        assert!(serde.features.is_empty());
+    }
+
+    #[test]
+    fn optional_activations_strong_slash_feature_activates_and_forwards() {
+        let activations = optional_activations(&set(&["serde/derive"]));
+        let serde = &activations["serde"];
+        assert!(serde.strong);
+        assert_eq!(serde.features, vec!["derive".to_string()]);
+    }
+
+    #[test]
+    fn optional_activations_weak_slash_feature_forwards_without_activating() {
+        let activations = optional_activations(&set(&["serde?/derive"]));
+        let serde = &activations["serde"];
+        assert!(!serde.strong);
+        assert_eq!(serde.features, vec!["derive".to_string()]);
+    }
+
+    #[test]
+    fn optional_activations_weak_then_strong_for_same_dep_is_strong() {
+        let activations = optional_activations(&set(&["serde?/derive", "dep:serde"]));
+        assert!(activations["serde"].strong);
+    }
+
+    #[test]
+    fn expand_features_follows_default_recursively() {
+        let mut features = HashMap::new();
+        features.insert("default".to_string(), vec!["a".to_string()]);
+        features.insert("a".to_string(), vec!["b".to_string()]);
+        let active = expand_features(&features, &["default".to_string()]);
+        assert_eq!(active, set(&["default", "a", "b"]));
+    }
+
+    #[test]
+    fn expand_features_handles_unknown_requested_feature() {
+        let features = HashMap::new();
+        let active = expand_features(&features, &["nonexistent".to_string()]);
+        assert_eq!(active, set(&["nonexistent"]));
+    }
+}
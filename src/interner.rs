@@ -0,0 +1,45 @@
+use std::collections::HashMap;
+
+/// An interned crate name: a small integer standing in for a `String`, so
+/// the dag and lookup tables can be keyed and copied around by value
+/// instead of hashing and comparing full strings at every lookup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Sym(u32);
+
+impl Sym {
+    /// The dense index this symbol corresponds to, suitable for indexing a
+    /// `Vec` built in interning order.
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// Maps crate names to `Sym`s and back. Each distinct name is stored once.
+#[derive(Debug, Default)]
+pub struct Interner {
+    names: Vec<String>,
+    syms: HashMap<String, Sym>,
+}
+
+impl Interner {
+    pub fn new() -> Interner {
+        Interner::default()
+    }
+
+    /// Intern `name`, returning its existing `Sym` if already seen, or
+    /// allocating a new one.
+    pub fn intern(&mut self, name: &str) -> Sym {
+        if let Some(&sym) = self.syms.get(name) {
+            return sym;
+        }
+        let sym = Sym(self.names.len() as u32);
+        self.names.push(name.to_string());
+        self.syms.insert(name.to_string(), sym);
+        sym
+    }
+
+    /// Resolve a `Sym` back to the name it was interned from.
+    pub fn resolve(&self, sym: Sym) -> &str {
+        &self.names[sym.0 as usize]
+    }
+}
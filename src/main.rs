@@ -3,24 +3,35 @@ extern crate daggy;
 #[macro_use]
 extern crate failure;
 extern crate fallible_iterator;
+extern crate rayon;
+extern crate reqwest;
 extern crate semver;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+extern crate sha2;
 extern crate walkdir;
 
+mod deps_stats;
+mod download;
+mod features;
+mod interner;
+
 use cargo::core::SourceId;
 use cargo::util::config::Config;
 use cargo::util::hex;
 use daggy::Dag;
+use deps_stats::DepsStats;
 use failure::{Error, ResultExt, SyncFailure};
 use fallible_iterator::FallibleIterator;
+use features::FeatureGraphBuilder;
+use interner::{Interner, Sym};
 use semver::{Version, VersionReq};
 use std::boxed::Box;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::fs::File;
+use std::fs::{self, File};
 use std::hash::{Hash, Hasher};
 use std::io::{BufRead, BufReader};
 use std::path::{Path};
@@ -80,6 +91,20 @@ fn list_registry_crates<P: AsRef<Path>>(regpath: P) -> Box<FallibleIterator<Item
             }))))
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum DependencyKind {
+    Normal,
+    Build,
+    Dev,
+}
+
+impl Default for DependencyKind {
+    fn default() -> Self {
+        DependencyKind::Normal
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 struct Dependency {
     name: String,
@@ -88,7 +113,14 @@ struct Dependency {
     optional: bool,
     default_features: bool,
     target: Option<String>,
-    kind: Option<String>,
+    #[serde(default)]
+    kind: DependencyKind,
+    // Present when the dependency is renamed locally via `package = "..."`,
+    // in which case `name` is the local alias and this is the real crate.
+    package: Option<String>,
+    // Present when the dependency comes from a registry other than
+    // crates.io.
+    registry: Option<String>,
 }
 
 impl fmt::Display for Dependency {
@@ -151,58 +183,218 @@ fn short_name(id: &SourceId) -> String {
     format!("{}-{}", ident, hash)
 }
 
-fn work() -> Result<(), Error> {
-    let config = Config::default().sync()?;
-    let sid = SourceId::crates_io(&config).sync()?;
-    let source_name = short_name(&sid);
-    let regpath = config.registry_index_path().into_path_unlocked().join(&source_name);
-    println!("regpath: {:?}", regpath);
-    // Get a vec of all crate versions, and insert them all into the dag.
-    let mut dag: Dag<(), ()> = Dag::new();
-    let crates: Vec<_> = find_crates(&regpath)?;
-    // Lookup by name.
-    let mut by_name = HashMap::new();
+/// Weight of a dependency edge: whether it's optional, and what kind of
+/// dependency (normal/build/dev) it is.
+#[derive(Debug, Clone, Copy)]
+struct EdgeData {
+    optional: bool,
+    kind: DependencyKind,
+}
+
+/// Pick the version cargo would actually select for `req`: the highest
+/// matching version that isn't yanked, falling back to the highest matching
+/// yanked version only if nothing unyanked matches. Returns the winning
+/// version's index within `versions` alongside the version itself, since
+/// callers key their node maps on that index rather than the version value.
+fn resolve_version<'v>(versions: &'v [CrateVersion], req: &VersionReq) -> Option<(u32, &'v CrateVersion)> {
+    let mut best: Option<(u32, &CrateVersion)> = None;
+    let mut best_yanked: Option<(u32, &CrateVersion)> = None;
+    for (i, v) in versions.iter().enumerate() {
+        if !req.matches(&v.version) {
+            continue;
+        }
+        let slot = if v.yanked { &mut best_yanked } else { &mut best };
+        if slot.map_or(true, |(_, cur)| v.version > cur.version) {
+            *slot = Some((i as u32, v));
+        }
+    }
+    best.or(best_yanked)
+}
+
+/// Pick the version cargo would treat as "current": the highest version
+/// that isn't yanked, falling back to the highest yanked version only if
+/// every release has been yanked. Same yanked-fallback rule as
+/// `resolve_version`, just without a requirement to filter by — callers
+/// that want "the latest version of this crate" should use this instead of
+/// assuming anything about `versions`' array order.
+fn latest_version<'v>(versions: &'v [CrateVersion]) -> (u32, &'v CrateVersion) {
+    let mut best: Option<(u32, &CrateVersion)> = None;
+    let mut best_yanked: Option<(u32, &CrateVersion)> = None;
+    for (i, v) in versions.iter().enumerate() {
+        let slot = if v.yanked { &mut best_yanked } else { &mut best };
+        if slot.map_or(true, |(_, cur)| v.version > cur.version) {
+            *slot = Some((i as u32, v));
+        }
+    }
+    best.or(best_yanked).expect("crate has no versions")
+}
+
+/// Intern every crate's name and return a `Vec` indexed directly by the
+/// resulting `Sym`, so looking a crate up by id is a plain index instead of
+/// a hashed string lookup. Relies on every crate in `crates` having a
+/// distinct name, which holds for a crates.io index; returns an error
+/// instead of silently corrupting the index/Sym mapping if that's ever
+/// violated.
+fn index_by_sym(crates: Vec<Crate>, interner: &mut Interner) -> Result<Vec<Crate>, Error> {
+    let mut by_sym = Vec::with_capacity(crates.len());
+    for c in crates {
+        let sym = interner.intern(&c.name);
+        if sym.index() != by_sym.len() {
+            return Err(format_err!(
+                "Duplicate crate name {:?}: interned as {}, but {} crates have \
+                 already been indexed; every Sym::index() from here on would be \
+                 off by one",
+                c.name, sym.index(), by_sym.len()));
+        }
+        by_sym.push(c);
+    }
+    Ok(by_sym)
+}
+
+/// Build the dependency DAG over `crates_by_sym`, including only edges
+/// whose `DependencyKind` is in `kinds`. Returns the dag along with lookup
+/// maps from `(crate, version index)` to its node, from crate to the node
+/// of its latest version, and from crate to the nodes of *all* its
+/// versions (so callers that need every node for a crate, e.g. reverse-dep
+/// traversal, don't have to scan the whole dag to find them).
+fn build_dag(crates_by_sym: &[Crate], interner: &mut Interner, kinds: &HashSet<DependencyKind>)
+    -> Result<(Dag<Sym, EdgeData>,
+               HashMap<(Sym, u32), daggy::NodeIndex>,
+               HashMap<Sym, daggy::NodeIndex>,
+               HashMap<Sym, Vec<daggy::NodeIndex>>), Error>
+{
+    // Each node is weighted with its crate's interned name so we can later
+    // answer by-crate queries (e.g. reverse-dependency stats) straight off
+    // the dag, without re-hashing strings.
+    let mut dag: Dag<Sym, EdgeData> = Dag::new();
     let mut crate_nodes = HashMap::new();
-    for c in crates.iter() {
-        by_name.insert(&c.name, c);
-        for v in c.versions.iter() {
-            crate_nodes.insert(v, dag.add_node(()));
+    let mut latest_nodes = HashMap::new();
+    let mut nodes_by_sym: HashMap<Sym, Vec<daggy::NodeIndex>> = HashMap::new();
+    for c in crates_by_sym.iter() {
+        let sym = interner.intern(&c.name);
+        let (latest_i, _) = latest_version(&c.versions);
+        for (i, _) in c.versions.iter().enumerate() {
+            let i = i as u32;
+            let idx = dag.add_node(sym);
+            crate_nodes.insert((sym, i), idx);
+            nodes_by_sym.entry(sym).or_insert_with(Vec::new).push(idx);
+            if i == latest_i {
+                latest_nodes.insert(sym, idx);
+            }
         }
     }
-    let get_dep = |dep: &Dependency| -> Option<&CrateVersion> {
-        by_name.get(&dep.name).and_then(|c| {
-            c.versions.iter().filter(|v| dep.req.matches(&v.version)).next()
-        })
-    };
-    let start = Instant::now();
-    for c in crates.iter() {
-        for v in c.versions.iter() {
-            let idx = *crate_nodes.get(v).unwrap();
+    for c in crates_by_sym.iter() {
+        let csym = interner.intern(&c.name);
+        for (vi, v) in c.versions.iter().enumerate() {
+            let idx = *crate_nodes.get(&(csym, vi as u32)).unwrap();
             for dep in v.deps.iter() {
-                // Just skip dev deps.
-                if let Some("dev") = dep.kind.as_ref().map(String::as_ref) {
+                if !kinds.contains(&dep.kind) {
+                    continue;
+                }
+                // Deps from a non-default registry aren't in this index, so
+                // there's no crate to resolve against; skip quietly rather
+                // than reporting a spurious failure.
+                if dep.registry.is_some() {
                     continue;
                 }
-                let depver = match get_dep(dep) {
-                    Some(s) => s,
+                // `package` holds the real crate name when `name` is just a
+                // local rename alias (`dep = { package = "real-name" }`).
+                let real_name = dep.package.as_ref().unwrap_or(&dep.name);
+                let dep_sym = interner.intern(real_name);
+                let target_crate = crates_by_sym.get(dep_sym.index())
+                    .filter(|c| c.name == *real_name);
+                let (dep_vi, depver) = match target_crate.and_then(|c| resolve_version(&c.versions, &dep.req)) {
+                    Some(r) => r,
                     None => {
                         println!("Failed to find dependency of {}: {}",
                                 v, dep);
                         continue;
                     }
                 };
-                let dep_idx = *crate_nodes.get(depver).unwrap();
+                let dep_idx = *crate_nodes.get(&(dep_sym, dep_vi)).unwrap();
                 //println!("{} -> {}", v, depver);
-                dag.add_edge(idx, dep_idx, ()).with_context(|e| {
+                let edge = EdgeData { optional: dep.optional, kind: dep.kind };
+                dag.add_edge(idx, dep_idx, edge).with_context(|e| {
                     format!("Failed to add edge from {} to {} ({}): {}",
                             v, dep, depver, e)
                 })?;
             }
         }
     }
-    println!("Built dag with {} nodes, {} edges in {}",
+    Ok((dag, crate_nodes, latest_nodes, nodes_by_sym))
+}
+
+/// Peak resident set size in KiB, read from `/proc/self/status` (Linux
+/// only; `None` elsewhere or if it can't be parsed).
+fn peak_rss_kb() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    status.lines()
+        .find(|l| l.starts_with("VmHWM:"))
+        .and_then(|l| l.split_whitespace().nth(1))
+        .and_then(|n| n.parse().ok())
+}
+
+fn work() -> Result<(), Error> {
+    let config = Config::default().sync()?;
+    let sid = SourceId::crates_io(&config).sync()?;
+    let source_name = short_name(&sid);
+    let regpath = config.registry_index_path().into_path_unlocked().join(&source_name);
+    println!("regpath: {:?}", regpath);
+    let crates = find_crates(&regpath)?;
+
+    let mut interner = Interner::new();
+    let crates = index_by_sym(crates, &mut interner)?;
+
+    // Build a normal+build dag; dev deps are excluded since they never
+    // affect what actually gets compiled into a dependent crate.
+    let kinds: HashSet<_> = [DependencyKind::Normal, DependencyKind::Build].iter().cloned().collect();
+    let start = Instant::now();
+    let (dag, _crate_nodes, latest_nodes, nodes_by_sym) = build_dag(&crates, &mut interner, &kinds)?;
+    println!("Built dag with {} nodes, {} edges in {} ({} peak RSS)",
              dag.node_count(), dag.edge_count(),
+             fmt_duration_as_secs(&start.elapsed()),
+             peak_rss_kb().map_or("unknown".to_string(), |kb| format!("{} KiB", kb)));
+
+    let start = Instant::now();
+    let stats = DepsStats::compute(&dag, &latest_nodes, &nodes_by_sym);
+    println!("Computed reverse-dep stats for {} crates in {}",
+             stats.total, fmt_duration_as_secs(&start.elapsed()));
+    println!("Most depended-upon crates:");
+    let top = stats.most_depended_upon(10);
+    for &(sym, deps) in &top {
+        println!("  {}: {} direct ({} default, {} optional), {} transitive",
+                 interner.resolve(sym), deps.direct.def + deps.direct.opt, deps.direct.def,
+                 deps.direct.opt, deps.transitive.len());
+    }
+
+    if let Some(&(top_sym, _)) = top.first() {
+        let root_crate = &crates[top_sym.index()];
+        let (_, root_version) = latest_version(&root_crate.versions);
+        let start = Instant::now();
+        let feature_builder = FeatureGraphBuilder::new(&crates);
+        let feature_dag = feature_builder.build_feature_graph(
+            root_version, &["default".to_string()], &mut interner);
+        println!("Built default-feature graph for {} {} with {} nodes, {} edges in {}",
+                 root_crate.name, root_version.version,
+                 feature_dag.node_count(), feature_dag.edge_count(),
+                 fmt_duration_as_secs(&start.elapsed()));
+    }
+
+    // Mirror and checksum-verify the latest version of the most
+    // depended-upon crates, as a sample of the index walker doubling as an
+    // integrity-checking tool.
+    let download_targets: Vec<CrateVersion> = top.iter()
+        .map(|&(sym, _)| latest_version(&crates[sym.index()].versions).1.clone())
+        .collect();
+    let dest_dir = Path::new("crate-cache");
+    let start = Instant::now();
+    let report = download::verify_crate_files(&download_targets, dest_dir, 4)?;
+    println!("Verified {} crate files ({} downloaded, {} already verified, \
+              {} checksum mismatches, {} errors) in {}",
+             download_targets.len(), report.downloaded, report.already_verified,
+             report.mismatches.len(), report.errors.len(),
              fmt_duration_as_secs(&start.elapsed()));
+
     Ok(())
 }
 
@@ -212,3 +404,53 @@ fn main() {
         Err(e) => println!("Error: {}, {}", e.cause(), e.backtrace()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(v: &str, yanked: bool) -> CrateVersion {
+        CrateVersion {
+            name: "foo".to_string(),
+            version: Version::parse(v).unwrap(),
+            deps: Vec::new(),
+            cksum: String::new(),
+            features: HashMap::new(),
+            yanked,
+        }
+    }
+
+    #[test]
+    fn resolve_version_picks_highest_matching_non_yanked() {
+        let versions = vec![version("1.0.0", false), version("1.2.0", false), version("1.1.0", false)];
+        let req = VersionReq::parse("*").unwrap();
+        let (i, v) = resolve_version(&versions, &req).unwrap();
+        assert_eq!(i, 1);
+        assert_eq!(v.version, Version::parse("1.2.0").unwrap());
+    }
+
+    #[test]
+    fn resolve_version_falls_back_to_yanked_if_nothing_else_matches() {
+        let versions = vec![version("1.0.0", true), version("1.1.0", true)];
+        let req = VersionReq::parse("*").unwrap();
+        let (i, v) = resolve_version(&versions, &req).unwrap();
+        assert_eq!(i, 1);
+        assert_eq!(v.version, Version::parse("1.1.0").unwrap());
+    }
+
+    #[test]
+    fn resolve_version_prefers_non_yanked_even_if_lower() {
+        let versions = vec![version("2.0.0", true), version("1.0.0", false)];
+        let req = VersionReq::parse("*").unwrap();
+        let (i, v) = resolve_version(&versions, &req).unwrap();
+        assert_eq!(i, 1);
+        assert_eq!(v.version, Version::parse("1.0.0").unwrap());
+    }
+
+    #[test]
+    fn resolve_version_returns_none_when_nothing_matches() {
+        let versions = vec![version("1.0.0", false)];
+        let req = VersionReq::parse("^2").unwrap();
+        assert!(resolve_version(&versions, &req).is_none());
+    }
+}